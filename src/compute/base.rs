@@ -14,7 +14,7 @@
 
 //! Foundation bits exposing the Compute API.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
 
 use reqwest::RequestBuilder;
@@ -34,6 +34,249 @@ const API_VERSION_KEYPAIR_PAGINATION: ApiVersion = ApiVersion(2, 35);
 const API_VERSION_FLAVOR_DESCRIPTION: ApiVersion = ApiVersion(2, 55);
 const API_VERSION_FLAVOR_EXTRA_SPECS: ApiVersion = ApiVersion(2, 61);
 
+/// Table of named Compute API features and the microversion that introduces
+/// them.
+const FEATURES: &[(&str, ApiVersion)] = &[
+    ("keypair_type", API_VERSION_KEYPAIR_TYPE),
+    ("keypair_pagination", API_VERSION_KEYPAIR_PAGINATION),
+    ("server_description", API_VERSION_SERVER_DESCRIPTION),
+    ("flavor_description", API_VERSION_FLAVOR_DESCRIPTION),
+    ("flavor_extra_specs", API_VERSION_FLAVOR_EXTRA_SPECS),
+];
+
+/// A set of Compute API capabilities negotiated once against the service.
+///
+/// A feature is considered supported when the endpoint's maximum
+/// microversion is at least the one listed for it in the feature table.
+#[derive(Clone, Debug)]
+pub struct ComputeCapabilities {
+    supported: HashMap<&'static str, ApiVersion>,
+}
+
+impl ComputeCapabilities {
+    fn new<T: V2API>(api: &T) -> Result<ComputeCapabilities> {
+        let mut supported = HashMap::new();
+        for (name, version) in FEATURES {
+            if api.resolve_version(SERVICE_TYPE, &[*version])?.is_some() {
+                let _ = supported.insert(*name, *version);
+            }
+        }
+        Ok(ComputeCapabilities { supported })
+    }
+
+    /// Names of the features supported by the server.
+    pub fn supported_features(&self) -> Vec<String> {
+        self.supported.keys().map(|name| name.to_string()).collect()
+    }
+
+    /// Minimum API version required for the given feature, or `None` if
+    /// `feature` is not a known feature name.
+    ///
+    /// This only looks up the static feature table, so unlike the rest of
+    /// `ComputeCapabilities` it does not require negotiating with a server.
+    pub fn requires(feature: &str) -> Option<ApiVersion> {
+        FEATURES
+            .iter()
+            .find(|(name, _)| *name == feature)
+            .map(|(_, version)| *version)
+    }
+
+    /// Whether the given feature is supported by the server.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.supported.contains_key(feature)
+    }
+
+    /// Pick the highest microversion among the given features that is
+    /// actually supported by the server, if any.
+    fn pick(&self, features: &[&str]) -> Option<ApiVersion> {
+        pick_supported_version(features, |feature| self.supports(feature))
+    }
+}
+
+/// Pick the highest microversion among `features` whose `supported`
+/// predicate returns `true`, if any.
+///
+/// Used by [`ComputeCapabilities::pick`]; `negotiate_version` and
+/// `pick_compute_api_version` apply their own rules and do not go through it.
+fn pick_supported_version<F: Fn(&str) -> bool>(
+    features: &[&str],
+    supported: F,
+) -> Option<ApiVersion> {
+    features
+        .iter()
+        .filter(|feature| supported(feature))
+        .filter_map(|feature| ComputeCapabilities::requires(feature))
+        .max()
+}
+
+/// Options controlling a paginated list request.
+#[derive(Clone, Debug, Default)]
+pub struct PaginationOptions {
+    limit: Option<u32>,
+    marker: Option<String>,
+}
+
+impl PaginationOptions {
+    /// Start building a new set of pagination options.
+    pub fn new() -> PaginationOptions {
+        PaginationOptions::default()
+    }
+
+    /// Request at most this many items per page.
+    pub fn limit(mut self, limit: u32) -> PaginationOptions {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Start listing after the item with the given marker (ID or name).
+    pub fn marker<S: Into<String>>(mut self, marker: S) -> PaginationOptions {
+        self.marker = Some(marker.into());
+        self
+    }
+
+    fn into_query(self) -> HashMap<String, String> {
+        let mut query = HashMap::new();
+        if let Some(limit) = self.limit {
+            let _ = query.insert("limit".to_string(), limit.to_string());
+        }
+        if let Some(marker) = self.marker {
+            let _ = query.insert("marker".to_string(), marker);
+        }
+        query
+    }
+}
+
+/// Whether to stop paginating after yielding the page that just came back.
+///
+/// Returns `true` once a page comes back smaller than the requested
+/// `limit` (meaning it was the last one), or once a page yields the same
+/// marker as the previous one, which means the server is not honouring
+/// `marker`/`limit` and would otherwise cause an infinite loop.
+///
+/// The `limit`-based check only fires when a `limit` was actually
+/// requested: with the default `PaginationOptions::new()` (no limit),
+/// termination relies entirely on the stalled-marker check and on the
+/// server eventually returning an empty page. Callers that want pagination
+/// to stop promptly on the last (possibly short) page should set a
+/// `limit`.
+fn should_stop_after_page(
+    page_len: usize,
+    limit: Option<usize>,
+    marker: &str,
+    previous_marker: Option<&str>,
+) -> bool {
+    let short_page = limit.map_or(false, |limit| page_len < limit);
+    let stalled = previous_marker == Some(marker);
+    short_page || stalled
+}
+
+/// A lazily paginated collection of resources.
+///
+/// Fetches further pages on demand as items are consumed, advancing the
+/// `marker` query parameter past the last item of the previous page.
+/// Iteration stops once a page comes back empty, or per
+/// [`should_stop_after_page`] once a short or stalled page comes back.
+pub struct ResourceIterator<'s, T> {
+    session: &'s Session,
+    query: HashMap<String, String>,
+    version: Option<ApiVersion>,
+    fetch:
+        Box<dyn Fn(&Session, &HashMap<String, String>, Option<ApiVersion>) -> Result<Vec<T>> + 's>,
+    marker_of: Box<dyn Fn(&T) -> String + 's>,
+    buffer: VecDeque<T>,
+    limit: Option<usize>,
+    last_marker: Option<String>,
+    done: bool,
+}
+
+impl<'s, T> ResourceIterator<'s, T> {
+    fn new<F, M>(
+        session: &'s Session,
+        query: HashMap<String, String>,
+        version: Option<ApiVersion>,
+        fetch: F,
+        marker_of: M,
+    ) -> ResourceIterator<'s, T>
+    where
+        F: Fn(&Session, &HashMap<String, String>, Option<ApiVersion>) -> Result<Vec<T>> + 's,
+        M: Fn(&T) -> String + 's,
+    {
+        let limit = query
+            .get("limit")
+            .and_then(|limit| limit.parse::<usize>().ok());
+        ResourceIterator {
+            session,
+            query,
+            version,
+            fetch: Box::new(fetch),
+            marker_of: Box::new(marker_of),
+            buffer: VecDeque::new(),
+            limit,
+            last_marker: None,
+            done: false,
+        }
+    }
+
+    /// A single-page iterator, used as a fallback when pagination support
+    /// cannot be negotiated with the server.
+    fn single_page(session: &'s Session, items: Vec<T>) -> ResourceIterator<'s, T> {
+        ResourceIterator {
+            session,
+            query: HashMap::new(),
+            version: None,
+            fetch: Box::new(|_, _, _| Ok(Vec::new())),
+            marker_of: Box::new(|_| String::new()),
+            buffer: items.into(),
+            limit: None,
+            last_marker: None,
+            done: true,
+        }
+    }
+}
+
+impl<'s, T> Iterator for ResourceIterator<'s, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if let Some(item) = self.buffer.pop_front() {
+            return Some(Ok(item));
+        }
+
+        if self.done {
+            return None;
+        }
+
+        match (self.fetch)(self.session, &self.query, self.version) {
+            Ok(page) => {
+                if page.is_empty() {
+                    self.done = true;
+                    return None;
+                }
+
+                let marker = (self.marker_of)(page.last().expect("checked above"));
+                let stop = should_stop_after_page(
+                    page.len(),
+                    self.limit,
+                    &marker,
+                    self.last_marker.as_deref(),
+                );
+
+                self.last_marker = Some(marker.clone());
+                let _ = self.query.insert("marker".to_string(), marker);
+                self.buffer = page.into();
+                if stop {
+                    self.done = true;
+                }
+                self.buffer.pop_front().map(Ok)
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
 /// Extensions for Session.
 pub trait V2API {
     /// Create a key pair.
@@ -42,6 +285,19 @@ pub trait V2API {
     /// Create a server.
     fn create_server(&self, request: protocol::ServerCreate) -> Result<Ref>;
 
+    /// Negotiate the set of Compute API capabilities supported by the
+    /// server.
+    ///
+    /// This re-resolves the capability set on every call. Memoizing it per
+    /// session would need a field owned by `Session` itself, which is not
+    /// something this trait can add; a cache keyed on the session's address
+    /// (e.g. via a global map) is not safe, since `Session` is a movable
+    /// value type and its address is neither stable across moves nor unique
+    /// once a session is dropped and the allocation is reused.
+    fn compute_capabilities(&self) -> Result<ComputeCapabilities> {
+        ComputeCapabilities::new(self)
+    }
+
     /// Delete a key pair.
     fn delete_keypair<S: AsRef<str>>(&self, name: S) -> Result<()>;
 
@@ -91,15 +347,39 @@ pub trait V2API {
     fn list_flavors_detail<Q: Serialize + Debug>(&self, query: &Q)
         -> Result<Vec<protocol::Flavor>>;
 
+    /// List flavors with details, transparently paginating through all pages.
+    fn list_flavors_detail_iter<Q: Serialize + Debug>(
+        &self,
+        query: &Q,
+        pagination: PaginationOptions,
+    ) -> Result<ResourceIterator<protocol::Flavor>>;
+
     /// List key pairs.
     fn list_keypairs<Q: Serialize + Debug>(&self, query: &Q) -> Result<Vec<protocol::KeyPair>>;
 
+    /// List key pairs, transparently paginating through all pages.
+    ///
+    /// Falls back to a single page if the server does not support key pair
+    /// pagination (microversion 2.35).
+    fn list_keypairs_iter<Q: Serialize + Debug>(
+        &self,
+        query: &Q,
+        pagination: PaginationOptions,
+    ) -> Result<ResourceIterator<protocol::KeyPair>>;
+
     /// List servers.
     fn list_servers<Q: Serialize + Debug>(
         &self,
         query: &Q,
     ) -> Result<Vec<common::protocol::IdAndName>>;
 
+    /// List servers, transparently paginating through all pages.
+    fn list_servers_iter<Q: Serialize + Debug>(
+        &self,
+        query: &Q,
+        pagination: PaginationOptions,
+    ) -> Result<ResourceIterator<common::protocol::IdAndName>>;
+
     /// List servers with details.
     fn list_servers_detail<Q: Serialize + Debug>(&self, query: &Q)
         -> Result<Vec<protocol::Server>>;
@@ -107,6 +387,30 @@ pub trait V2API {
     /// Pick the highest API version or None if neither is supported.
     fn pick_compute_api_version(&self, versions: &[ApiVersion]) -> Result<Option<ApiVersion>>;
 
+    /// Resolve the negotiated microversion for a candidate set.
+    ///
+    /// This forwards to `pick_compute_api_version` on every call: nothing is
+    /// memoized and `service_type` is not used. A per-session cache would
+    /// need a field owned by `Session` itself, which this trait cannot add;
+    /// a cache keyed on the session's address is not sound, since a
+    /// `Session` is a movable value whose address changes across moves and
+    /// gets reused once a session is dropped. Callers that need to avoid
+    /// repeated negotiation should resolve the version once and hold onto
+    /// it themselves.
+    fn resolve_version(
+        &self,
+        service_type: &'static str,
+        versions: &[ApiVersion],
+    ) -> Result<Option<ApiVersion>> {
+        let _ = service_type;
+        self.pick_compute_api_version(versions)
+    }
+
+    /// Pick the version to request for the given set of features.
+    fn pick_compute_feature_version(&self, features: &[&str]) -> Result<Option<ApiVersion>> {
+        Ok(self.compute_capabilities()?.pick(features))
+    }
+
     /// Run an action while providing some arguments.
     fn server_action_with_args<S1, S2, Q>(&self, id: S1, action: S2, args: Q) -> Result<()>
     where
@@ -136,19 +440,102 @@ pub trait V2API {
 #[derive(Copy, Clone, Debug)]
 pub struct V2;
 
+impl V2 {
+    /// Symbolic microversion meaning "whatever the server considers latest",
+    /// sent as the literal `latest` value (see [`version_header_value`])
+    /// instead of a pinned number.
+    ///
+    /// This is represented as `ApiVersion(0, 0)` because `set_api_version_headers`
+    /// below takes a plain `ApiVersion` — that signature comes from the
+    /// `ServiceType` trait, defined outside this checkout, so a distinct
+    /// `latest`-vs-pinned enum cannot be threaded through it here. Treat the
+    /// sentinel as a special case rather than an orderable version: it must
+    /// never be passed through `.max()`/capability-table comparisons (which
+    /// would treat it as the lowest, not the latest, version), only
+    /// forwarded directly to a request as an explicit, caller-chosen
+    /// override. `major_version_supported` special-cases it for the same
+    /// reason.
+    pub const LATEST: ApiVersion = ApiVersion(0, 0);
+}
+
 const SERVICE_TYPE: &str = "compute";
 
 fn flavor_api_version<T: V2API>(api: &T) -> Result<Option<ApiVersion>> {
-    api.pick_compute_api_version(&[
-        API_VERSION_FLAVOR_DESCRIPTION,
-        API_VERSION_FLAVOR_EXTRA_SPECS,
-    ])
+    api.pick_compute_feature_version(&["flavor_description", "flavor_extra_specs"])
+}
+
+/// Flatten a query together with pagination options into a single
+/// string-keyed map that `ResourceIterator` can mutate as it advances the
+/// marker between pages.
+///
+/// Only a query that serializes to a JSON object (a struct or a map)
+/// contributes parameters; any other shape, such as a slice of key/value
+/// pairs, contributes none. Non-string values, `null` included, are kept as
+/// their JSON text.
+fn merge_query<Q: Serialize + Debug>(
+    query: &Q,
+    pagination: PaginationOptions,
+) -> Result<HashMap<String, String>> {
+    let mut merged = HashMap::new();
+    if let serde_json::Value::Object(fields) = serde_json::to_value(query)? {
+        for (key, value) in fields {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                other => other.to_string(),
+            };
+            let _ = merged.insert(key, value);
+        }
+    }
+    merged.extend(pagination.into_query());
+    Ok(merged)
+}
+
+fn version_header_value(version: ApiVersion) -> String {
+    if version == V2::LATEST {
+        "latest".to_string()
+    } else {
+        version.to_string()
+    }
+}
+
+/// Make sure a specific, required microversion is actually within the
+/// `[min_version, version]` range discovered from the endpoint's version
+/// document, clamping up to `min_version` if needed and erroring out
+/// clearly if `required` exceeds the server's maximum instead of silently
+/// sending a microversion the server does not understand.
+fn negotiate_version(session: &Session, required: ApiVersion) -> Result<ApiVersion> {
+    if required == V2::LATEST {
+        return Ok(required);
+    }
+
+    let info = session.get_service_info_ref::<V2>()?;
+    if let Some(max) = info.version {
+        if required > max {
+            return Err(format!(
+                "Compute API microversion {} is required here, but the server's maximum \
+                 supported microversion is {}",
+                required, max
+            )
+            .into());
+        }
+    }
+
+    Ok(match info.min_version {
+        Some(min) if required < min => min,
+        _ => required,
+    })
 }
 
 impl V2API for Session {
     fn create_keypair(&self, request: protocol::KeyPairCreate) -> Result<protocol::KeyPair> {
         let version = if request.key_type.is_some() {
-            Some(API_VERSION_KEYPAIR_TYPE)
+            // Only the static feature table is needed here, so there is no
+            // need to negotiate the full capability set just for this check.
+            Some(negotiate_version(
+                self,
+                ComputeCapabilities::requires("keypair_type")
+                    .expect("\"keypair_type\" is a known compute feature"),
+            )?)
         } else {
             None
         };
@@ -234,7 +621,7 @@ impl V2API for Session {
 
     fn get_keypair<S: AsRef<str>>(&self, name: S) -> Result<protocol::KeyPair> {
         trace!("Get compute key pair by name {}", name.as_ref());
-        let ver = self.pick_compute_api_version(&[API_VERSION_KEYPAIR_TYPE])?;
+        let ver = self.pick_compute_feature_version(&["keypair_type"])?;
         let keypair = self
             .get::<V2>(&["os-keypairs", name.as_ref()], ver)?
             .receive_json::<protocol::KeyPairRoot>()?
@@ -245,7 +632,7 @@ impl V2API for Session {
 
     fn get_server_by_id<S: AsRef<str>>(&self, id: S) -> Result<protocol::Server> {
         trace!("Get compute server with ID {}", id.as_ref());
-        let version = self.pick_compute_api_version(&[API_VERSION_SERVER_DESCRIPTION])?;
+        let version = self.pick_compute_feature_version(&["server_description"])?;
         let server = self
             .get::<V2>(&["servers", id.as_ref()], version)?
             .receive_json::<protocol::ServerRoot>()?
@@ -290,7 +677,7 @@ impl V2API for Session {
         query: &Q,
     ) -> Result<Vec<protocol::Flavor>> {
         trace!("Listing compute flavors with {:?}", query);
-        let version = self.pick_compute_api_version(&[API_VERSION_FLAVOR_EXTRA_SPECS])?;
+        let version = self.pick_compute_feature_version(&["flavor_extra_specs"])?;
         let result = self
             .get::<V2>(&["flavors", "detail"], version)?
             .query(query)
@@ -300,12 +687,32 @@ impl V2API for Session {
         Ok(result)
     }
 
+    fn list_flavors_detail_iter<Q: Serialize + Debug>(
+        &self,
+        query: &Q,
+        pagination: PaginationOptions,
+    ) -> Result<ResourceIterator<protocol::Flavor>> {
+        trace!("Iterating compute flavors with {:?}", query);
+        let version = self.pick_compute_feature_version(&["flavor_extra_specs"])?;
+        let query = merge_query(query, pagination)?;
+        Ok(ResourceIterator::new(
+            self,
+            query,
+            version,
+            |session, query, version| {
+                Ok(session
+                    .get::<V2>(&["flavors", "detail"], version)?
+                    .query(query)
+                    .receive_json::<protocol::FlavorsDetailRoot>()?
+                    .flavors)
+            },
+            |item: &protocol::Flavor| item.id.clone(),
+        ))
+    }
+
     fn list_keypairs<Q: Serialize + Debug>(&self, query: &Q) -> Result<Vec<protocol::KeyPair>> {
         trace!("Listing compute key pairs with {:?}", query);
-        let ver = self.pick_compute_api_version(&[
-            API_VERSION_KEYPAIR_TYPE,
-            API_VERSION_KEYPAIR_PAGINATION,
-        ])?;
+        let ver = self.pick_compute_feature_version(&["keypair_type", "keypair_pagination"])?;
         let result = self
             .get::<V2>(&["os-keypairs"], ver)?
             .query(query)
@@ -318,6 +725,41 @@ impl V2API for Session {
         Ok(result)
     }
 
+    fn list_keypairs_iter<Q: Serialize + Debug>(
+        &self,
+        query: &Q,
+        pagination: PaginationOptions,
+    ) -> Result<ResourceIterator<protocol::KeyPair>> {
+        trace!("Iterating compute key pairs with {:?}", query);
+        let caps = self.compute_capabilities()?;
+        if !caps.supports("keypair_pagination") {
+            debug!("Key pair pagination is not supported, falling back to a single page");
+            return Ok(ResourceIterator::single_page(
+                self,
+                self.list_keypairs(query)?,
+            ));
+        }
+
+        let version = caps.pick(&["keypair_type", "keypair_pagination"]);
+        let query = merge_query(query, pagination)?;
+        Ok(ResourceIterator::new(
+            self,
+            query,
+            version,
+            |session, query, version| {
+                Ok(session
+                    .get::<V2>(&["os-keypairs"], version)?
+                    .query(query)
+                    .receive_json::<protocol::KeyPairsRoot>()?
+                    .keypairs
+                    .into_iter()
+                    .map(|item| item.keypair)
+                    .collect::<Vec<_>>())
+            },
+            |item: &protocol::KeyPair| item.name.clone(),
+        ))
+    }
+
     fn list_servers<Q: Serialize + Debug>(
         &self,
         query: &Q,
@@ -332,12 +774,34 @@ impl V2API for Session {
         Ok(result)
     }
 
+    fn list_servers_iter<Q: Serialize + Debug>(
+        &self,
+        query: &Q,
+        pagination: PaginationOptions,
+    ) -> Result<ResourceIterator<common::protocol::IdAndName>> {
+        trace!("Iterating compute servers with {:?}", query);
+        let query = merge_query(query, pagination)?;
+        Ok(ResourceIterator::new(
+            self,
+            query,
+            None,
+            |session, query, version| {
+                Ok(session
+                    .get::<V2>(&["servers"], version)?
+                    .query(query)
+                    .receive_json::<protocol::ServersRoot>()?
+                    .servers)
+            },
+            |item: &common::protocol::IdAndName| item.id.clone(),
+        ))
+    }
+
     fn list_servers_detail<Q: Serialize + Debug>(
         &self,
         query: &Q,
     ) -> Result<Vec<protocol::Server>> {
         trace!("Listing compute servers with {:?}", query);
-        let version = self.pick_compute_api_version(&[API_VERSION_SERVER_DESCRIPTION])?;
+        let version = self.pick_compute_feature_version(&["server_description"])?;
         let result = self
             .get::<V2>(&["servers", "detail"], version)?
             .query(query)
@@ -393,14 +857,111 @@ impl ServiceType for V2 {
     }
 
     fn major_version_supported(version: ApiVersion) -> bool {
-        version.0 == 2
+        version == V2::LATEST || version.0 == 2
     }
 
     fn set_api_version_headers(
         request: RequestBuilder,
         version: ApiVersion,
     ) -> Result<RequestBuilder> {
-        // TODO: new-style header support
-        Ok(request.header("x-openstack-nova-api-version", version.to_string()))
+        let value = version_header_value(version);
+        Ok(request
+            .header("x-openstack-nova-api-version", value.clone())
+            .header("OpenStack-API-Version", format!("compute {}", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick_supported_version, should_stop_after_page, ApiVersion, ComputeCapabilities};
+
+    #[test]
+    fn requires_looks_up_known_features() {
+        assert_eq!(
+            ComputeCapabilities::requires("keypair_type"),
+            Some(ApiVersion(2, 2))
+        );
+        assert_eq!(
+            ComputeCapabilities::requires("keypair_pagination"),
+            Some(ApiVersion(2, 35))
+        );
+    }
+
+    #[test]
+    fn requires_returns_none_for_unknown_feature() {
+        assert_eq!(ComputeCapabilities::requires("not_a_real_feature"), None);
+    }
+
+    #[test]
+    fn pick_supported_version_picks_the_highest_supported() {
+        let features = &["keypair_type", "keypair_pagination", "server_description"];
+        let picked = pick_supported_version(features, |feature| {
+            feature == "keypair_type" || feature == "keypair_pagination"
+        });
+        assert_eq!(picked, Some(ApiVersion(2, 35)));
+    }
+
+    #[test]
+    fn pick_supported_version_ignores_unknown_feature_names() {
+        let features = &["keypair_type", "not_a_real_feature"];
+        let picked = pick_supported_version(features, |_| true);
+        assert_eq!(picked, Some(ApiVersion(2, 2)));
+    }
+
+    #[test]
+    fn pick_supported_version_is_none_when_nothing_supported() {
+        let features = &["keypair_type", "keypair_pagination"];
+        let picked = pick_supported_version(features, |_| false);
+        assert_eq!(picked, None);
+    }
+
+    #[test]
+    fn full_page_with_no_limit_does_not_stop() {
+        assert!(!should_stop_after_page(10, None, "marker-a", None));
+    }
+
+    #[test]
+    fn full_page_under_limit_does_not_stop() {
+        assert!(!should_stop_after_page(
+            10,
+            Some(10),
+            "marker-a",
+            Some("marker-before")
+        ));
+    }
+
+    #[test]
+    fn short_page_under_limit_stops() {
+        assert!(should_stop_after_page(
+            3,
+            Some(10),
+            "marker-a",
+            Some("marker-before")
+        ));
+    }
+
+    #[test]
+    fn short_page_with_no_limit_does_not_stop_on_its_own() {
+        assert!(!should_stop_after_page(
+            3,
+            None,
+            "marker-a",
+            Some("marker-before")
+        ));
+    }
+
+    #[test]
+    fn repeated_marker_stops_even_under_the_limit() {
+        assert!(should_stop_after_page(
+            10,
+            Some(10),
+            "same-marker",
+            Some("same-marker")
+        ));
+    }
+
+    #[test]
+    fn first_page_never_stalls() {
+        assert!(!should_stop_after_page(10, Some(10), "marker-a", None));
     }
 }